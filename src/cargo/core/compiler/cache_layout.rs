@@ -10,80 +10,111 @@
 //!     A cache version to allow breaking changes in the cache structure.
 //!     $CACHE_VERSION/
 //!
-//!         # File used to lock the directory to prevent multiple cargo processes
-//!         # from using it at the same time.
-//!         .cargo-lock
+//!         A short hash of the active rustc and the environment variables
+//!         that can affect the build (see `env_hash` below). This keeps
+//!         artifacts built with different toolchains or flags (e.g.
+//!         switching `RUSTFLAGS`) from colliding with each other.
+//!         $ENV_HASH/
 //!
-//!         # Hidden directory that holds all of the fingerprint files for all
-//!         # packages
-//!         .fingerprint/
-//!             # Each package is in a separate directory.
-//!             # Note that different target kinds have different filename prefixes.
-//!             $pkgname-$META/
-//!                 # Set of source filenames for this package.
-//!                 dep-lib-$targetname
-//!                 # Timestamp when this package was last built.
-//!                 invoked.timestamp
-//!                 # The fingerprint hash.
-//!                 lib-$targetname
-//!                 # Detailed information used for logging the reason why
-//!                 # something is being recompiled.
-//!                 lib-$targetname.json
-//!                 # The console output from the compiler. This is cached
-//!                 # so that warnings can be redisplayed for "fresh" units.
-//!                 output-lib-$targetname
+//!             # Shared (read) lock held for the duration of the build. Taking
+//!             # it as a shared lock, rather than exclusive, is what lets two
+//!             # cargo processes with disjoint crate graphs build against this
+//!             # cache concurrently.
+//!             .cargo-lock
 //!
-//!         # This is the root directory for all rustc artifacts except build
-//!         # scripts, examples, and test and bench executables. Almost every
-//!         # artifact should have a metadata hash added to its filename to
-//!         # prevent collisions. One notable exception is dynamic libraries.
-//!         deps/
+//!             # Hidden directory that holds all of the fingerprint files for all
+//!             # packages
+//!             .fingerprint/
+//!                 # Each package is in a separate directory.
+//!                 # Note that different target kinds have different filename prefixes.
+//!                 $pkgname-$META/
+//!                     # Exclusive lock for this unit specifically, held only
+//!                     # while it is actually being compiled. See
+//!                     # `CacheLayout::lock_unit`.
+//!                     .lock
+//!                     # Set of source filenames for this package.
+//!                     dep-lib-$targetname
+//!                     # Timestamp when this package was last built.
+//!                     invoked.timestamp
+//!                     # The fingerprint hash.
+//!                     lib-$targetname
+//!                     # Detailed information used for logging the reason why
+//!                     # something is being recompiled.
+//!                     lib-$targetname.json
+//!                     # The console output from the compiler. This is cached
+//!                     # so that warnings can be redisplayed for "fresh" units.
+//!                     output-lib-$targetname
 //!
-//!         # This is the location at which the output of all custom build
-//!         # commands are rooted.
-//!         build/
+//!             # This is the root directory for all rustc artifacts except build
+//!             # scripts, examples, and test and bench executables. Almost every
+//!             # artifact should have a metadata hash added to its filename to
+//!             # prevent collisions. One notable exception is dynamic libraries.
+//!             deps/
 //!
-//!             # Each package gets its own directory where its build script and
-//!             # script output are placed
-//!             $pkgname-$META/    # For the build script itself.
-//!                 # The build script executable (name may be changed by user).
-//!                 build-script-build-$META
-//!                 # Hard link to build-script-build-$META.
-//!                 build-script-build
-//!                 # Dependency information generated by rustc.
-//!                 build-script-build-$META.d
-//!                 # Debug information, depending on platform and profile
-//!                 # settings.
-//!                 <debug symbols>
+//!             # This is the location at which the output of all custom build
+//!             # commands are rooted.
+//!             build/
 //!
-//!             # The package shows up twice with two different metadata hashes.
-//!             $pkgname-$META/  # For the output of the build script.
-//!                 # Timestamp when the build script was last executed.
-//!                 invoked.timestamp
-//!                 # Directory where script can output files ($OUT_DIR).
-//!                 out/
-//!                 # Output from the build script.
-//!                 output
-//!                 # Path to `out`, used to help when the target directory is
-//!                 # moved.
-//!                 root-output
-//!                 # Stderr output from the build script.
-//!                 stderr
+//!                 # Each package gets its own directory where its build script and
+//!                 # script output are placed
+//!                 $pkgname-$META/    # For the build script itself.
+//!                     # The build script executable (name may be changed by user).
+//!                     build-script-build-$META
+//!                     # Hard link to build-script-build-$META.
+//!                     build-script-build
+//!                     # Dependency information generated by rustc.
+//!                     build-script-build-$META.d
+//!                     # Debug information, depending on platform and profile
+//!                     # settings.
+//!                     <debug symbols>
+//!
+//!                 # The package shows up twice with two different metadata hashes.
+//!                 $pkgname-$META/  # For the output of the build script.
+//!                     # Timestamp when the build script was last executed.
+//!                     invoked.timestamp
+//!                     # Directory where script can output files ($OUT_DIR).
+//!                     out/
+//!                     # Output from the build script.
+//!                     output
+//!                     # Path to `out`, used to help when the target directory is
+//!                     # moved.
+//!                     root-output
+//!                     # Stderr output from the build script.
+//!                     stderr
 //! ```
 
 use crate::core::compiler::Context;
+use crate::util::config::Config;
+use crate::util::hex::short_hash;
 use crate::util::paths;
 use crate::util::{CargoResult, FileLock, Filesystem};
+use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 /// The cache version, make sure to increment this if you make any
 /// breaking changes to the cache folder!
 const CACHE_VERSION: &str = "0";
 
+/// Prefixes of environment variables that are hashed into the `$ENV_HASH`
+/// cache segment by default. Users can extend this list with
+/// `cache.env-hash-prefixes` in their cargo config to partition the cache on
+/// additional variables that affect the build (e.g. a custom linker wrapper).
+///
+/// Deliberately narrow: this should only cover variables that actually
+/// influence compiled output. A bare `CARGO` prefix, for instance, would
+/// also catch `CARGO_HOME`/`CARGO_TARGET_DIR`/`CARGO_NET_OFFLINE` and the
+/// like, fragmenting the cache across machines and sessions that would
+/// otherwise safely share artifacts -- defeating the cross-workspace
+/// sharing the rest of this cache is built around.
+const DEFAULT_ENV_HASH_PREFIXES: &[&str] = &["RUSTFLAGS", "RUSTC", "CC", "CFLAGS", "CXX", "CMAKE"];
+
 /// Contains the paths of all cache output locations.
 ///
 /// See module docs for more information.
-pub struct CacheLayout {
+pub struct CacheLayout<'cfg> {
     /// The root directory: most likely `$CARGO_HOME/.cargo/cache`
     root: PathBuf,
     /// The directory for the current cache version: `$root/$VERSION`
@@ -94,22 +125,44 @@ pub struct CacheLayout {
     build: PathBuf,
     /// The directory for fingerprints: `$dest/.fingerprint`
     fingerprint: PathBuf,
-    /// The lockfile for the cache (`.cargo-lock`). Will be unlocked when this
-    /// struct is `drop`ped.
-    _lock: FileLock,
+    /// The shared (read) lock on `.cargo-lock`, held for the build's
+    /// duration. `None` only while [`CacheLayout::prune`] has temporarily
+    /// released it to take the cache root's lock exclusively; unlocked for
+    /// good when this struct is `drop`ped.
+    _lock: Option<FileLock>,
+    /// When this `CacheLayout` was created. Units written to the cache at or
+    /// after this time belong to the current build and are never evicted by
+    /// [`CacheLayout::prune`], regardless of how old they'd otherwise look.
+    build_started_at: SystemTime,
+    /// Used by [`CacheLayout::lock_unit`] to take per-unit locks.
+    config: &'cfg Config,
+    /// The `$pkgname-$META` of every unit in this build's plan, recorded via
+    /// [`CacheLayout::mark_unit_used`] -- for units that were recompiled,
+    /// [`CacheLayout::lock_unit`] does this automatically, but fresh
+    /// (cache-hit) units are never locked and so must be marked explicitly by
+    /// the caller. [`CacheLayout::materialize_into`] only links these units
+    /// into `target/`, since `deps`/`build` here are the cache shared across
+    /// every workspace on the machine, not just this one.
+    ///
+    /// A `Mutex`, not a `RefCell`: `mark_unit_used` and `lock_unit` are called
+    /// from whichever compile-worker thread drives each unit's build, so this
+    /// needs to be `Sync`.
+    built_units: Mutex<Vec<String>>,
 }
 
-impl CacheLayout {
+impl<'cfg> CacheLayout<'cfg> {
     /// Calculate the paths for cache output, lock the cache directory, and return as a CacheLayout.
     ///
-    /// This function will block if the directory is already locked.
-    pub fn new(
-        cx: &Context<'_, '_>,
-    ) -> CargoResult<Option<CacheLayout>> {
+    /// This function will block if the directory is already locked exclusively
+    /// by another process, e.g. while it's being pruned (see
+    /// [`CacheLayout::prune`]).
+    pub fn new(cx: &Context<'_, 'cfg>) -> CargoResult<Option<CacheLayout<'cfg>>> {
         if let Some(root) = &cx.bcx.config.cache_dir()? {
             // let mut root = ws.target_dir();
             let root = root.clone();
-            let dest = root.join(CACHE_VERSION);
+            let dest = root
+                .join(CACHE_VERSION)
+                .join(env_hash(cx.bcx.config, &cx.bcx.rustc().verbose_version)?);
             // If the root directory doesn't already exist go ahead and create it
             // here. Use this opportunity to exclude it from backups as well if the
             // system supports it since this is a freshly created folder.
@@ -118,10 +171,18 @@ impl CacheLayout {
                 exclude_from_backups(dest.as_path_unlocked());
             }
 
-            // For now we don't do any more finer-grained locking on the artifact
-            // directory, so just lock the entire thing for the duration of this
-            // compile.
-            let lock = dest.open_rw(".cargo-lock", cx.bcx.config, "build directory")?;
+            // The root lock only needs to be *shared* for the duration of the
+            // build: it exists to keep a prune pass (which needs exclusive
+            // access, see `prune`) from running concurrently with a build,
+            // not to serialize builds against each other. Mutual exclusion
+            // between builds writing the same unit is handled per-unit by
+            // `lock_unit`, so two cargo processes building disjoint crate
+            // graphs can share this cache at the same time.
+            let lock_path = dest.as_path_unlocked().join(".cargo-lock");
+            if !lock_path.exists() {
+                fs::File::create(&lock_path)?;
+            }
+            let lock = dest.open_ro(".cargo-lock", cx.bcx.config, "build directory")?;
             let root = root.into_path_unlocked();
             let dest = dest.into_path_unlocked();
 
@@ -131,13 +192,52 @@ impl CacheLayout {
                 fingerprint: dest.join(".fingerprint"),
                 root,
                 dest,
-                _lock: lock,
+                _lock: Some(lock),
+                build_started_at: SystemTime::now(),
+                config: cx.bcx.config,
+                built_units: Mutex::new(Vec::new()),
             }))
         } else {
             Ok(None)
         }
     }
 
+    /// Acquires an exclusive lock on a single cached unit, identified by its
+    /// `$pkgname-$META` fingerprint directory name.
+    ///
+    /// Hold the returned guard only while that specific unit is being
+    /// compiled and its artifacts are being written into `deps/`/`build/`.
+    /// This is what lets two cargo processes building disjoint crate graphs
+    /// proceed in parallel against one cache — the common CI scenario where
+    /// multiple jobs point at the same cache directory — while still
+    /// preventing two processes from racing to write the same artifact.
+    ///
+    /// Also records `pkg_meta` as belonging to this build, via
+    /// [`CacheLayout::mark_unit_used`], so [`CacheLayout::materialize_into`]
+    /// knows to link it into `target/`.
+    pub fn lock_unit(&self, pkg_meta: &str) -> CargoResult<FileLock> {
+        let unit_dir = Filesystem::new(self.fingerprint.join(pkg_meta));
+        unit_dir.create_dir()?;
+        let lock = unit_dir.open_rw(
+            ".lock",
+            self.config,
+            &format!("cache entry for `{}`", pkg_meta),
+        )?;
+        self.mark_unit_used(pkg_meta);
+        Ok(lock)
+    }
+
+    /// Records `pkg_meta` as part of this build's unit graph, whether it was
+    /// just recompiled or served straight from the cache as a fresh hit.
+    ///
+    /// [`CacheLayout::lock_unit`] calls this for every unit it recompiles,
+    /// but a unit that's already fresh is never locked at all, so the caller
+    /// is responsible for calling this directly for those so
+    /// [`CacheLayout::materialize_into`] still links them into `target/`.
+    pub fn mark_unit_used(&self, pkg_meta: &str) {
+        self.built_units.lock().unwrap().push(pkg_meta.to_string());
+    }
+
     /// Makes sure all directories stored in the Layout exist on the filesystem.
     pub fn prepare(&mut self) -> CargoResult<()> {
         paths::create_dir_all(&self.deps)?;
@@ -167,6 +267,444 @@ impl CacheLayout {
     pub fn build(&self) -> &Path {
         &self.build
     }
+
+    /// Projects this build's units -- and only this build's units, i.e. the
+    /// ones recorded via [`CacheLayout::mark_unit_used`] (automatic for
+    /// recompiled units via [`CacheLayout::lock_unit`], but the caller must
+    /// mark fresh cache hits explicitly) -- into the conventional
+    /// `target/<profile>/` layout, for tooling that expects a workspace-local
+    /// `target/` rather than the shared cache.
+    ///
+    /// `deps` and `build` under the cache root are shared across every
+    /// workspace on the machine (see the module docs), so linking them
+    /// wholesale would dump every unit ever built by any project into this
+    /// one workspace's `target/`. Restricting to this build's units keeps
+    /// `target/` scoped the way users expect.
+    ///
+    /// Mirrors the hardlink-with-fallback approach the rust build system
+    /// uses to stitch its stage directories together: each file is hardlinked
+    /// in, falling back to a symlink and then a full copy on filesystems that
+    /// don't support hardlinks (e.g. across a mount point). Only files that
+    /// are missing or stale in the destination are re-linked, so repeated
+    /// calls are idempotent and don't duplicate gigabytes of cache data.
+    /// Besides populating `deps/`/`build/`, each unit's final rustc output is
+    /// also linked straight into the profile root (e.g. `target/debug/foo`),
+    /// which is the path cargo and downstream tooling actually look at.
+    pub fn materialize_into(&self, target_dir: &Path, profile: &str) -> CargoResult<()> {
+        let profile_dir = target_dir.join(profile);
+        for pkg_meta in self.built_units.lock().unwrap().iter() {
+            link_unit_tree(&self.deps, &profile_dir.join("deps"), pkg_meta)?;
+            link_unit_tree(&self.build, &profile_dir.join("build"), pkg_meta)?;
+            link_final_outputs(&self.deps, &profile_dir, pkg_meta)?;
+        }
+        Ok(())
+    }
+
+    /// Evicts least-recently-used units from the cache until the total size
+    /// of cached artifacts is at or under `max_bytes`.
+    ///
+    /// "Recently used" is tracked per-unit via the mtime of its
+    /// `invoked.timestamp` file under `.fingerprint/$pkgname-$META/`. Units
+    /// whose timestamp was written during this build are never evicted, even
+    /// if they're otherwise the oldest, since evicting them would force an
+    /// immediate rebuild.
+    ///
+    /// Unlike a build, which only needs a *shared* lock on `.cargo-lock` (see
+    /// [`CacheLayout::new`]), pruning deletes unit directories outright and so
+    /// needs the cache root locked exclusively for the sweep's duration.
+    ///
+    /// `flock` locks are scoped to an open file description, not a process,
+    /// so simply opening a second, exclusive lock on `.cargo-lock` while
+    /// `self`'s own shared lock is still open would wait forever on a lock
+    /// only `self` itself could release — a guaranteed self-deadlock. To
+    /// avoid that, this first drops `self`'s shared lock, then takes the
+    /// exclusive lock for the sweep, then reacquires the shared lock
+    /// afterwards so the rest of the build can keep relying on it. Because
+    /// that's a mutation of `self`'s lock state, this takes `&mut self`.
+    pub fn prune(&mut self, max_bytes: u64) -> CargoResult<()> {
+        let root = Filesystem::new(self.dest.clone());
+
+        // Release our own shared hold before requesting the exclusive lock
+        // below, or the request would block on itself forever.
+        self._lock = None;
+
+        let result = self.sweep(&root, max_bytes);
+
+        // Always try to go back to holding the cache root shared for the
+        // remainder of the build, even if the sweep above failed, so a
+        // failed prune doesn't silently leave `self` holding no lock at all
+        // for the rest of the build.
+        self._lock = Some(root.open_ro(".cargo-lock", self.config, "build directory")?);
+
+        result
+    }
+
+    /// Does the actual eviction work for [`CacheLayout::prune`], under an
+    /// exclusive lock on the cache root.
+    fn sweep(&self, root: &Filesystem, max_bytes: u64) -> CargoResult<()> {
+        let _prune_lock = root.open_rw(".cargo-lock", self.config, "cache prune")?;
+
+        let mut units = self.cached_units()?;
+        let mut total: u64 = units.iter().map(|u| u.size).sum();
+        if total <= max_bytes {
+            return Ok(());
+        }
+
+        // Oldest first.
+        units.sort_by_key(|u| u.last_used);
+
+        for unit in &units {
+            if total <= max_bytes {
+                break;
+            }
+            if unit.last_used >= self.build_started_at {
+                // Written during this build; never evict.
+                continue;
+            }
+            self.evict_unit(unit)?;
+            total = total.saturating_sub(unit.size);
+        }
+
+        Ok(())
+    }
+
+    /// Enumerates every `$pkgname-$META` unit currently tracked in
+    /// `.fingerprint/`, along with its last-access time and on-disk size.
+    fn cached_units(&self) -> CargoResult<Vec<CachedUnit>> {
+        let mut units = Vec::new();
+        let entries = match fs::read_dir(&self.fingerprint) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(units),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let pkg_meta = entry.file_name().to_string_lossy().into_owned();
+            let last_used = match fs::metadata(entry.path().join("invoked.timestamp")) {
+                Ok(meta) => meta.modified()?,
+                // No timestamp yet (e.g. build in progress); treat as brand new
+                // so it's never picked for eviction.
+                Err(_) => SystemTime::now(),
+            };
+            let size = dir_size(&entry.path())?
+                + deps_size(&self.deps, &pkg_meta)?
+                + dir_size(&self.build.join(&pkg_meta))?;
+            units.push(CachedUnit {
+                pkg_meta,
+                last_used,
+                size,
+            });
+        }
+        Ok(units)
+    }
+
+    /// Removes every on-disk trace of a single cached unit: its fingerprint
+    /// directory, its `deps/` artifacts, and its `build/` directory.
+    fn evict_unit(&self, unit: &CachedUnit) -> CargoResult<()> {
+        let fingerprint_dir = self.fingerprint.join(&unit.pkg_meta);
+        if fingerprint_dir.exists() {
+            paths::remove_dir_all(&fingerprint_dir)?;
+        }
+        for path in matching_deps(&self.deps, &unit.pkg_meta)? {
+            paths::remove_file(&path)?;
+        }
+        let build_dir = self.build.join(&unit.pkg_meta);
+        if build_dir.exists() {
+            paths::remove_dir_all(&build_dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single cached compilation unit, as tracked under `.fingerprint/`.
+struct CachedUnit {
+    /// The `$pkgname-$META` directory name under `.fingerprint/`.
+    pkg_meta: String,
+    /// Last time this unit was built, taken from `invoked.timestamp`.
+    last_used: SystemTime,
+    /// Total size in bytes of the fingerprint, deps, and build directories
+    /// for this unit.
+    size: u64,
+}
+
+/// Whether a directory entry's name belongs to the unit identified by
+/// `pkg_meta` (cache entries are named `$pkgname-$META` or
+/// `$pkgname-$META.ext`), the one prefix rule every cache-entry lookup in
+/// this module shares.
+fn belongs_to_unit(file_name: &std::ffi::OsStr, pkg_meta: &str) -> bool {
+    match file_name.to_string_lossy().strip_prefix(pkg_meta) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.'),
+        None => false,
+    }
+}
+
+/// Returns every path directly under `deps` that belongs to `pkg_meta`
+/// (rustc artifacts are named `$pkgname-$META.ext`).
+fn matching_deps(deps: &Path, pkg_meta: &str) -> CargoResult<Vec<PathBuf>> {
+    let mut matches = Vec::new();
+    let entries = match fs::read_dir(deps) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(matches),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if belongs_to_unit(&entry.file_name(), pkg_meta) {
+            matches.push(entry.path());
+        }
+    }
+    Ok(matches)
+}
+
+/// Sums the size of every `deps/` artifact belonging to `pkg_meta`.
+fn deps_size(deps: &Path, pkg_meta: &str) -> CargoResult<u64> {
+    let mut total = 0;
+    for path in matching_deps(deps, pkg_meta)? {
+        total += fs::metadata(path)?.len();
+    }
+    Ok(total)
+}
+
+/// Recursively sums the size of every file under `path`. Returns `0` if
+/// `path` doesn't exist.
+fn dir_size(path: &Path) -> CargoResult<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Reads the configured cache size budget (`cache.max-size`, e.g. `"5GB"`),
+/// if any, as a number of bytes.
+pub fn cache_max_size(config: &Config) -> CargoResult<Option<u64>> {
+    match config.get::<Option<String>>("cache.max-size")? {
+        Some(s) => Ok(Some(parse_byte_size(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses a human-readable byte size like `"512MB"` or `"5GB"` (also
+/// accepting a bare number of bytes) into a byte count.
+fn parse_byte_size(s: &str) -> CargoResult<u64> {
+    let s = s.trim();
+    let (digits, suffix) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| anyhow::format_err!("invalid cache size `{}`", s))?;
+    let multiplier: u64 = match suffix.trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" | "K" => 1_000,
+        "MB" | "M" => 1_000_000,
+        "GB" | "G" => 1_000_000_000,
+        "TB" | "T" => 1_000_000_000_000,
+        other => anyhow::bail!("invalid cache size suffix `{}` in `{}`", other, s),
+    };
+    Ok(n * multiplier)
+}
+
+/// Computes the `$ENV_HASH` cache segment: a short hash of the active rustc
+/// plus every environment variable whose name starts with one of the
+/// configured prefixes.
+///
+/// This keeps artifacts built with different toolchains or compiler flags
+/// (e.g. flipping `RUSTFLAGS`) from landing in the same cache partition,
+/// while builds with an identical environment keep sharing artifacts.
+///
+/// `rustc_verbose_version` should be the `rustc -vV` output cargo already
+/// captured for the compiler it resolved to build with (`cx.bcx.rustc()`),
+/// not a fresh invocation of whatever `rustc` happens to be on `$PATH` --
+/// those can differ under `RUSTC`/rustup toolchain overrides, which would
+/// otherwise silently hash the wrong compiler's identity.
+fn env_hash(config: &Config, rustc_verbose_version: &str) -> CargoResult<String> {
+    let prefixes = env_hash_prefixes(config)?;
+
+    // `env::vars()` panics if *any* variable in the process environment is
+    // not valid Unicode, even one we'd otherwise filter out below. Use
+    // `vars_os()` instead, skipping any key that isn't valid UTF-8 (such a
+    // key is left out of the hash even if it starts with one of the
+    // prefixes) and only lossily converting the value.
+    let mut vars: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(k, v)| {
+            let key = k.to_str()?;
+            if prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+            {
+                Some((key.to_string(), v.to_string_lossy().into_owned()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    // Sort for determinism; `env::vars_os()` iteration order is unspecified.
+    vars.sort();
+
+    let mut to_hash = toolchain_fingerprint(rustc_verbose_version);
+    for (k, v) in vars {
+        to_hash.push('\u{0}');
+        to_hash.push_str(&k);
+        to_hash.push('=');
+        to_hash.push_str(&v);
+    }
+
+    Ok(short_hash(&to_hash))
+}
+
+/// Returns the list of environment variable prefixes that contribute to the
+/// `$ENV_HASH` cache segment: always [`DEFAULT_ENV_HASH_PREFIXES`], followed
+/// by any extra prefixes configured under `cache.env-hash-prefixes`.
+fn env_hash_prefixes(config: &Config) -> CargoResult<Vec<String>> {
+    let mut prefixes: Vec<String> = DEFAULT_ENV_HASH_PREFIXES
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    if let Some(extra) = config.get::<Option<Vec<String>>>("cache.env-hash-prefixes")? {
+        prefixes.extend(extra);
+    }
+    Ok(prefixes)
+}
+
+/// Extracts the parts of an already-captured `rustc -vV` that identify the
+/// active toolchain, so that switching toolchains lands builds in a fresh
+/// cache partition.
+fn toolchain_fingerprint(verbose_version: &str) -> String {
+    verbose_version
+        .lines()
+        .filter(|line| line.starts_with("commit-hash:") || line.starts_with("release:"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Links only the entries in `src` that belong to `pkg_meta` (i.e. named
+/// `pkg_meta` or `pkg_meta.ext`) into `dst`, recursing into matched directories.
+/// This is what keeps [`CacheLayout::materialize_into`] from dumping the
+/// entire shared, cross-workspace cache into one workspace's `target/`.
+fn link_unit_tree(src: &Path, dst: &Path, pkg_meta: &str) -> CargoResult<()> {
+    if !src.exists() {
+        return Ok(());
+    }
+    paths::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        if !belongs_to_unit(&entry.file_name(), pkg_meta) {
+            continue;
+        }
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_tree(&src_path, &dst_path)?;
+        } else {
+            link_file(&src_path, &dst_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Links a unit's final rustc outputs -- the `deps/` artifacts that aren't
+/// `.d` dep-info files -- straight into the profile root, e.g.
+/// `target/debug/foo`, which is the path cargo and downstream tooling
+/// actually expect rather than the hashed `deps/foo-$META`.
+fn link_final_outputs(deps: &Path, profile_dir: &Path, pkg_meta: &str) -> CargoResult<()> {
+    let output_name = strip_metadata_suffix(pkg_meta);
+    paths::create_dir_all(profile_dir)?;
+    for path in matching_deps(deps, pkg_meta)? {
+        if path.extension().map_or(false, |ext| ext == "d") {
+            continue;
+        }
+        let mut dst = profile_dir.join(output_name);
+        if let Some(ext) = path.extension() {
+            dst.set_extension(ext);
+        }
+        link_file(&path, &dst)?;
+    }
+    Ok(())
+}
+
+/// Strips the trailing `-$META` hash segment from a `$pkgname-$META`
+/// fingerprint directory name, yielding the plain name cargo uses at the
+/// profile root (e.g. `foo` rather than `foo-1a2b3c4d`).
+fn strip_metadata_suffix(pkg_meta: &str) -> &str {
+    match pkg_meta.rfind('-') {
+        Some(idx) => &pkg_meta[..idx],
+        None => pkg_meta,
+    }
+}
+
+/// Recursively links every file under `src` into the matching path under
+/// `dst`, creating destination directories as needed. A no-op if `src`
+/// doesn't exist (e.g. a unit has no `build/` output).
+fn link_tree(src: &Path, dst: &Path) -> CargoResult<()> {
+    if !src.exists() {
+        return Ok(());
+    }
+    paths::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_tree(&src_path, &dst_path)?;
+        } else {
+            link_file(&src_path, &dst_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Links (or copies) a single cached artifact into its `target/` location,
+/// skipping the work if the destination is already up to date.
+fn link_file(src: &Path, dst: &Path) -> CargoResult<()> {
+    if is_up_to_date(src, dst)? {
+        return Ok(());
+    }
+    if dst.exists() {
+        paths::remove_file(dst)?;
+    }
+    if fs::hard_link(src, dst).is_ok() {
+        return Ok(());
+    }
+    if symlink_file(src, dst).is_ok() {
+        return Ok(());
+    }
+    // Neither hardlinks nor symlinks are supported on this filesystem;
+    // fall back to a plain copy.
+    fs::copy(src, dst)?;
+    Ok(())
+}
+
+/// A destination artifact is up to date if it exists and is at least as new
+/// as its source, the same staleness check the fingerprint module uses for
+/// rustc inputs and outputs.
+fn is_up_to_date(src: &Path, dst: &Path) -> CargoResult<bool> {
+    if !dst.exists() {
+        return Ok(false);
+    }
+    let src_mtime = fs::metadata(src)?.modified()?;
+    let dst_mtime = fs::metadata(dst)?.modified()?;
+    Ok(dst_mtime >= src_mtime)
+}
+
+#[cfg(unix)]
+fn symlink_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(windows)]
+fn symlink_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(src, dst)
 }
 
 #[cfg(not(target_os = "macos"))]
@@ -197,3 +735,360 @@ fn exclude_from_backups(path: &Path) {
     // Errors are ignored, since it's an optional feature and failure
     // doesn't prevent Cargo from working
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn toolchain_fingerprint_keeps_only_commit_hash_and_release() {
+        let verbose_version = "\
+rustc 1.70.0 (90c541806 2023-05-31)
+binary: rustc
+commit-hash: 90c541806f23a127002de5b4038be731ba1458ca
+commit-date: 2023-05-31
+host: x86_64-unknown-linux-gnu
+release: 1.70.0
+LLVM version: 16.0.2
+";
+        assert_eq!(
+            toolchain_fingerprint(verbose_version),
+            "commit-hash: 90c541806f23a127002de5b4038be731ba1458ca\nrelease: 1.70.0"
+        );
+    }
+
+    #[test]
+    fn toolchain_fingerprint_differs_across_releases() {
+        let a = "commit-hash: aaaa\nrelease: 1.70.0\n";
+        let b = "commit-hash: bbbb\nrelease: 1.71.0\n";
+        assert_ne!(toolchain_fingerprint(a), toolchain_fingerprint(b));
+    }
+
+    #[test]
+    fn env_hash_prefixes_default_excludes_cargo_itself() {
+        let config = Config::default().unwrap();
+        let prefixes = env_hash_prefixes(&config).unwrap();
+        // A bare `CARGO` prefix would also catch `CARGO_HOME`/`CARGO_TARGET_DIR`,
+        // fragmenting the cache for no build-affecting reason -- see the doc
+        // comment on `DEFAULT_ENV_HASH_PREFIXES`.
+        assert!(!prefixes.iter().any(|p| p == "CARGO"));
+        assert!(prefixes.iter().any(|p| p == "RUSTFLAGS"));
+    }
+
+    #[test]
+    fn env_hash_ignores_non_utf8_env_vars() {
+        // Regression test: `env_hash` must not panic just because *some*
+        // variable somewhere in the process environment is non-UTF-8, even
+        // if that variable doesn't match any configured prefix.
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let non_utf8 = OsStr::from_bytes(&[0xff, 0xfe, 0xfd]);
+            env::set_var("CARGO_CACHE_LAYOUT_TEST_NON_UTF8", non_utf8);
+            let config = Config::default().unwrap();
+            let result = env_hash(&config, "commit-hash: aaaa\nrelease: 1.70.0\n");
+            env::remove_var("CARGO_CACHE_LAYOUT_TEST_NON_UTF8");
+            assert!(result.is_ok());
+        }
+    }
+
+    #[test]
+    fn parse_byte_size_accepts_bare_bytes_and_suffixes() {
+        assert_eq!(parse_byte_size("1024").unwrap(), 1024);
+        assert_eq!(parse_byte_size("1024B").unwrap(), 1024);
+        assert_eq!(parse_byte_size("5KB").unwrap(), 5_000);
+        assert_eq!(parse_byte_size("5MB").unwrap(), 5_000_000);
+        assert_eq!(parse_byte_size("5GB").unwrap(), 5_000_000_000);
+        assert_eq!(parse_byte_size("2TB").unwrap(), 2_000_000_000_000);
+        // Case-insensitive suffix, surrounding whitespace tolerated.
+        assert_eq!(parse_byte_size(" 5gb ").unwrap(), 5_000_000_000);
+    }
+
+    #[test]
+    fn parse_byte_size_rejects_garbage() {
+        assert!(parse_byte_size("five gigabytes").is_err());
+        assert!(parse_byte_size("5XB").is_err());
+        assert!(parse_byte_size("").is_err());
+    }
+
+    #[test]
+    fn belongs_to_unit_matches_on_prefix_only() {
+        use std::ffi::OsStr;
+
+        assert!(belongs_to_unit(OsStr::new("foo-abc123"), "foo-abc123"));
+        assert!(belongs_to_unit(OsStr::new("foo-abc123.d"), "foo-abc123"));
+        // Must not match a different unit that merely shares a prefix.
+        assert!(!belongs_to_unit(OsStr::new("foo-abc1234"), "foo-abc123"));
+        assert!(!belongs_to_unit(OsStr::new("bar-abc123"), "foo-abc123"));
+    }
+
+    #[test]
+    fn matching_deps_finds_only_this_units_artifacts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let deps = tmp.path();
+        fs::write(deps.join("foo-abc123"), b"").unwrap();
+        fs::write(deps.join("foo-abc123.d"), b"").unwrap();
+        fs::write(deps.join("bar-def456"), b"").unwrap();
+
+        let mut matches = matching_deps(deps, "foo-abc123")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        matches.sort();
+        assert_eq!(matches, vec!["foo-abc123", "foo-abc123.d"]);
+    }
+
+    #[test]
+    fn matching_deps_on_missing_dir_is_empty_not_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let missing = tmp.path().join("does-not-exist");
+        assert_eq!(
+            matching_deps(&missing, "foo-abc123").unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    /// Builds a `CacheLayout` rooted at `tmp` without going through
+    /// `CacheLayout::new` (which needs a full `Context`), so `sweep` and
+    /// friends can be exercised directly against synthetic fingerprint dirs.
+    fn test_layout<'cfg>(
+        tmp: &Path,
+        config: &'cfg Config,
+        build_started_at: SystemTime,
+    ) -> CacheLayout<'cfg> {
+        CacheLayout {
+            root: tmp.to_path_buf(),
+            dest: tmp.to_path_buf(),
+            deps: tmp.join("deps"),
+            build: tmp.join("build"),
+            fingerprint: tmp.join(".fingerprint"),
+            _lock: None,
+            build_started_at,
+            config,
+            built_units: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates a fake fingerprint dir for `pkg_meta` containing one file of
+    /// `size` bytes, with `invoked.timestamp`'s mtime set to `last_used`.
+    fn write_fake_unit(fingerprint: &Path, pkg_meta: &str, size: u64, last_used: SystemTime) {
+        let dir = fingerprint.join(pkg_meta);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data"), vec![0u8; size as usize]).unwrap();
+        let timestamp = dir.join("invoked.timestamp");
+        fs::write(&timestamp, b"").unwrap();
+        filetime::set_file_mtime(&timestamp, filetime::FileTime::from_system_time(last_used))
+            .unwrap();
+    }
+
+    #[test]
+    fn sweep_evicts_oldest_first_and_spares_units_from_this_build() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::default().unwrap();
+        let build_started_at = SystemTime::now();
+
+        let ancient = build_started_at - Duration::from_secs(2000);
+        let older = build_started_at - Duration::from_secs(1000);
+        // Written during the current build, so it must survive even though
+        // it would otherwise be a perfectly good eviction candidate.
+        let fresh = build_started_at + Duration::from_secs(1);
+
+        let layout = test_layout(tmp.path(), &config, build_started_at);
+        write_fake_unit(&layout.fingerprint, "ancient-111", 100, ancient);
+        write_fake_unit(&layout.fingerprint, "older-222", 100, older);
+        write_fake_unit(&layout.fingerprint, "fresh-333", 100, fresh);
+
+        // Only a little over budget: evicts just the single oldest unit.
+        layout
+            .sweep(&Filesystem::new(tmp.path().to_path_buf()), 250)
+            .unwrap();
+        assert!(!layout.fingerprint.join("ancient-111").exists());
+        assert!(layout.fingerprint.join("older-222").exists());
+        assert!(layout.fingerprint.join("fresh-333").exists());
+
+        // Force eviction of everything evictable: `fresh` must still survive
+        // because it belongs to the current build.
+        layout
+            .sweep(&Filesystem::new(tmp.path().to_path_buf()), 0)
+            .unwrap();
+        assert!(!layout.fingerprint.join("older-222").exists());
+        assert!(layout.fingerprint.join("fresh-333").exists());
+    }
+
+    #[test]
+    fn sweep_is_a_no_op_when_already_under_budget() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::default().unwrap();
+        let build_started_at = SystemTime::now();
+        let layout = test_layout(tmp.path(), &config, build_started_at);
+        write_fake_unit(
+            &layout.fingerprint,
+            "small-111",
+            10,
+            build_started_at - Duration::from_secs(1000),
+        );
+
+        layout
+            .sweep(&Filesystem::new(tmp.path().to_path_buf()), 1_000_000)
+            .unwrap();
+        assert!(layout.fingerprint.join("small-111").exists());
+    }
+
+    #[test]
+    fn lock_unit_creates_the_unit_dir_and_marks_it_used() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::default().unwrap();
+        let layout = test_layout(tmp.path(), &config, SystemTime::now());
+
+        let _guard = layout.lock_unit("foo-abc123").unwrap();
+        assert!(layout.fingerprint.join("foo-abc123").is_dir());
+        assert!(layout.fingerprint.join("foo-abc123/.lock").exists());
+        assert_eq!(
+            layout.built_units.lock().unwrap().as_slice(),
+            &["foo-abc123".to_string()]
+        );
+    }
+
+    #[test]
+    fn lock_unit_allows_two_different_units_to_lock_concurrently() {
+        // The point of per-unit locking: two disjoint units don't contend
+        // with each other, only same-unit access is serialized.
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::default().unwrap();
+        let layout = test_layout(tmp.path(), &config, SystemTime::now());
+
+        let _a = layout.lock_unit("foo-abc123").unwrap();
+        let _b = layout.lock_unit("bar-def456").unwrap();
+        assert_eq!(layout.built_units.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn mark_unit_used_records_fresh_cache_hits_without_locking() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::default().unwrap();
+        let layout = test_layout(tmp.path(), &config, SystemTime::now());
+
+        layout.mark_unit_used("foo-abc123");
+        assert_eq!(
+            layout.built_units.lock().unwrap().as_slice(),
+            &["foo-abc123".to_string()]
+        );
+        // Marking a fresh hit doesn't create any on-disk lock.
+        assert!(!layout.fingerprint.join("foo-abc123").exists());
+    }
+
+    #[test]
+    fn link_file_hardlinks_when_the_destination_is_missing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dst = tmp.path().join("dst");
+        fs::write(&src, b"hello").unwrap();
+
+        link_file(&src, &dst).unwrap();
+
+        assert_eq!(fs::read(&dst).unwrap(), b"hello");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(
+                fs::metadata(&src).unwrap().ino(),
+                fs::metadata(&dst).unwrap().ino()
+            );
+        }
+    }
+
+    #[test]
+    fn link_file_replaces_a_stale_destination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dst = tmp.path().join("dst");
+        fs::write(&src, b"new-bytes").unwrap();
+        fs::write(&dst, b"stale-bytes").unwrap();
+        let earlier =
+            filetime::FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(&dst, earlier).unwrap();
+
+        link_file(&src, &dst).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"new-bytes");
+    }
+
+    #[test]
+    fn link_file_skips_work_when_destination_already_up_to_date() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dst = tmp.path().join("dst");
+        fs::write(&src, b"hello").unwrap();
+        fs::write(&dst, b"stale-but-newer-bytes").unwrap();
+        // `dst` already exists and is at least as new as `src`, so
+        // `is_up_to_date` should report it fresh and `link_file` must leave
+        // its contents alone rather than re-linking over them.
+        let now = filetime::FileTime::from_system_time(SystemTime::now());
+        filetime::set_file_mtime(&src, now).unwrap();
+        filetime::set_file_mtime(&dst, now).unwrap();
+
+        assert!(is_up_to_date(&src, &dst).unwrap());
+        link_file(&src, &dst).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"stale-but-newer-bytes");
+    }
+
+    #[test]
+    fn is_up_to_date_is_false_when_destination_is_missing_or_stale() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dst = tmp.path().join("dst");
+        fs::write(&src, b"hello").unwrap();
+        assert!(!is_up_to_date(&src, &dst).unwrap());
+
+        fs::write(&dst, b"old").unwrap();
+        let earlier =
+            filetime::FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(&dst, earlier).unwrap();
+        assert!(!is_up_to_date(&src, &dst).unwrap());
+    }
+
+    #[test]
+    fn strip_metadata_suffix_drops_the_trailing_hash_segment() {
+        assert_eq!(strip_metadata_suffix("foo-1a2b3c4d"), "foo");
+        assert_eq!(strip_metadata_suffix("foo"), "foo");
+    }
+
+    #[test]
+    fn link_final_outputs_skips_dep_info_and_strips_the_metadata_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let deps = tmp.path().join("deps");
+        fs::create_dir_all(&deps).unwrap();
+        fs::write(deps.join("foo-abc123"), b"binary").unwrap();
+        fs::write(deps.join("foo-abc123.d"), b"dep-info").unwrap();
+        let profile_dir = tmp.path().join("debug");
+
+        link_final_outputs(&deps, &profile_dir, "foo-abc123").unwrap();
+
+        assert_eq!(fs::read(profile_dir.join("foo")).unwrap(), b"binary");
+        assert!(!profile_dir.join("foo.d").exists());
+    }
+
+    #[test]
+    fn materialize_into_only_links_units_marked_as_used() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::default().unwrap();
+        let layout = test_layout(tmp.path(), &config, SystemTime::now());
+        fs::create_dir_all(&layout.deps).unwrap();
+        fs::write(layout.deps.join("used-111"), b"used").unwrap();
+        fs::write(layout.deps.join("unused-222"), b"unused").unwrap();
+
+        layout.mark_unit_used("used-111");
+        let target_dir = tmp.path().join("target");
+        layout.materialize_into(&target_dir, "debug").unwrap();
+
+        assert!(target_dir.join("debug/deps/used-111").exists());
+        assert!(target_dir.join("debug/used").exists());
+        // The unused unit belongs to some other workspace sharing this cache
+        // and must not leak into this one's `target/`.
+        assert!(!target_dir.join("debug/deps/unused-222").exists());
+        assert!(!target_dir.join("debug/unused").exists());
+    }
+}